@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdout, ExitStatus};
+use std::thread::JoinHandle;
+
+/// Wraps a spawned [`Child`] and exposes its stdout as a blocking [`Read`], draining stderr on
+/// a background thread into an in-memory buffer instead of forwarding it live.
+///
+/// This gives callers an idiomatic streaming reader they can wrap in a `BufReader` or pipe into
+/// any other sink, without re-implementing the piped-draining dance by hand. If the child exits
+/// zero, captured stderr is written through to the real stderr, so warnings aren't silently
+/// dropped; if the child exits non-zero, the captured stderr is instead folded into the
+/// `std::io::Error` returned from the read that observes EOF (and not written through), so
+/// failures aren't silently swallowed either.
+pub struct CommandReader {
+    child: Child,
+    stdout: ChildStdout,
+    stderr_thread: Option<JoinHandle<Vec<u8>>>,
+    status: Option<ExitStatus>,
+}
+
+impl CommandReader {
+    /// Wraps an already-spawned child whose stdout and stderr were both piped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `child`'s stdout or stderr were not configured as [`Stdio::piped`](std::process::Stdio::piped).
+    pub fn new(mut child: Child) -> Self {
+        let stdout = child
+            .stdout
+            .take()
+            .expect("CommandReader requires a child spawned with piped stdout");
+        let mut stderr = child
+            .stderr
+            .take()
+            .expect("CommandReader requires a child spawned with piped stderr");
+
+        // drain stderr into memory as it arrives, so the child never blocks on a full stderr
+        // pipe while we're only reading stdout.
+        let stderr_thread = std::thread::spawn(move || {
+            let mut captured = Vec::new();
+            let _ = stderr.read_to_end(&mut captured);
+            captured
+        });
+
+        Self {
+            child,
+            stdout,
+            stderr_thread: Some(stderr_thread),
+            status: None,
+        }
+    }
+
+    /// Returns the child's exit status, once a read has observed a clean EOF on stdout.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+}
+
+impl Read for CommandReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+
+        // clean EOF on stdout: join the stderr thread and check the exit status before
+        // reporting EOF to the caller.
+        let captured_stderr = match self.stderr_thread.take() {
+            Some(handle) => handle.join().unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let status = self.child.wait()?;
+        self.status = Some(status);
+
+        if !status.success() {
+            let stderr_text = String::from_utf8_lossy(&captured_stderr);
+            return Err(std::io::Error::other(format!(
+                "command exited with {status}, stderr: {stderr_text}"
+            )));
+        }
+
+        if !captured_stderr.is_empty() {
+            // the child exited cleanly but still wrote to stderr (warnings, etc.) - forward it
+            // instead of silently dropping it.
+            let _ = std::io::stderr().write_all(&captured_stderr);
+        }
+
+        Ok(0)
+    }
+}
@@ -1,8 +1,11 @@
+mod command_reader;
+mod stream_sink;
+
 use clap::{Parser, ValueEnum};
+use command_reader::CommandReader;
 use std::{
-    io::{BufRead, BufReader, Write},
-    process::{Command, Stdio},
-    thread::JoinHandle,
+    io::{Read, Write},
+    process::{ChildStderr, ChildStdout, Command, Stdio},
 };
 
 /// Defines the operating mode.
@@ -16,6 +19,13 @@ enum With {
     Piped,
     /// Uses piped IO (and reads the result to prevent buffering) for command spawning.
     PipedProcess,
+    /// Uses piped IO (and drains both streams from a single thread via readiness polling)
+    /// for command spawning.
+    PipedSelect,
+    /// Uses piped IO (and copies raw bytes, rather than UTF-8 lines) for command spawning.
+    PipedRaw,
+    /// Uses piped IO (and streams the output to a connected TCP/WebSocket client) for command spawning.
+    PipedStream,
 }
 
 impl std::fmt::Display for With {
@@ -34,6 +44,10 @@ struct Args {
     #[clap(long, short, default_value_t = With::Default)]
     with: With,
 
+    /// The address to listen on for a watcher client, when running with `piped-stream`.
+    #[clap(long, default_value = "127.0.0.1:7878")]
+    listen_addr: String,
+
     /// The command (optionally followed by arguments) to run.
     #[clap(last = true, required = true)]
     command: Vec<String>,
@@ -51,8 +65,30 @@ struct Args {
 ///
 /// Finally, try the following to see the safe-usage of "piped" IO, reading from the pipe to ensure the buffer isn't filled:
 /// - `cargo run -- -w piped-process -- cmd.exe /c type long_file.txt`
+///
+/// `piped-process` fixes the hang with one thread per stream. `piped-select` fixes the same
+/// hang from a single thread, by polling both pipes for readiness instead:
+/// - `cargo run -- -w piped-select -- cmd.exe /c type long_file.txt`
+///
+/// `piped-raw` drains the same buffers with one thread per stream, copying raw bytes so binary
+/// output passes through unmodified:
+/// - `cargo run -- -w piped-raw -- cmd.exe /c type some_binary_file`
+///
+/// Every piped mode also forwards this process's stdin into the child, so interactive programs
+/// that read from stdin (e.g. `python`) work, and the repro can reproduce the other half of the
+/// deadlock: a child blocked writing to a full stdout pipe while we're blocked writing to a full
+/// stdin pipe.
+///
+/// `piped-stream` drains the same way, but relays each chunk to a connected TCP (or WebSocket)
+/// client instead of our own stdout/stderr, so a remote watcher can follow a long-running
+/// command live:
+/// - `cargo run -- -w piped-stream --listen-addr 127.0.0.1:7878 -- cmd.exe /c type long_file.txt`
 fn main() {
-    let Args { with, command } = Args::parse();
+    let Args {
+        with,
+        listen_addr,
+        command,
+    } = Args::parse();
 
     let mut child = Command::new(&command[0])
         .args(&command[1..])
@@ -61,77 +97,331 @@ fn main() {
             With::Null => Stdio::null(),
             With::Piped => Stdio::piped(),
             With::PipedProcess => Stdio::piped(),
+            With::PipedSelect => Stdio::piped(),
+            With::PipedRaw => Stdio::piped(),
+            With::PipedStream => Stdio::piped(),
         })
         .stderr(match with {
             With::Default => Stdio::inherit(),
             With::Null => Stdio::null(),
             With::Piped => Stdio::piped(),
             With::PipedProcess => Stdio::piped(),
+            With::PipedSelect => Stdio::piped(),
+            With::PipedRaw => Stdio::piped(),
+            With::PipedStream => Stdio::piped(),
+        })
+        .stdin(match with {
+            With::Default => Stdio::inherit(),
+            With::Null => Stdio::null(),
+            With::Piped => Stdio::piped(),
+            With::PipedProcess => Stdio::piped(),
+            With::PipedSelect => Stdio::piped(),
+            With::PipedRaw => Stdio::piped(),
+            With::PipedStream => Stdio::piped(),
         })
         .spawn()
         .unwrap_or_else(|_| panic!("Failed to spawn process {:?}", command));
 
-    // storage for thread handles if we are implementing the fix
-    // otherwise, will be left empty
-    let mut thread_handles: Vec<JoinHandle<()>> = Vec::new();
+    // forward our own stdin into the child's until we hit EOF, then let the child's stdin
+    // handle close (by dropping it), so interactive children that read from stdin work, and
+    // so the repro can demonstrate the other half of the deadlock: a child that blocks writing
+    // to a full stdout pipe while the parent blocks writing to a full stdin pipe.
+    if with != With::Default && with != With::Null {
+        let child_stdin = child
+            .stdin
+            .take()
+            .expect("Failed to obtain stdin for piped mode");
+        std::thread::spawn(move || copy_raw(std::io::stdin(), child_stdin));
+    }
 
-    // to implement the fix, we create threads that process the piped input
+    // PipedProcess reads stdout through a CommandReader, which drains stderr into memory on
+    // our behalf and folds it into an error if the child fails, instead of the line-by-line
+    // thread pair this mode used to hand-roll.
     if with == With::PipedProcess {
+        let mut reader = CommandReader::new(child);
+        let mut process_stdout = std::io::stdout();
+
+        if let Err(e) = std::io::copy(&mut reader, &mut process_stdout) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+
+        let exit_code = exit_code_for(
+            reader
+                .status()
+                .expect("status is set once stdout reaches EOF"),
+        );
+
+        eprintln!(
+            "Executed '{:?}' with '{:?}', got exit code '{:?}'",
+            &command[0],
+            &command[1..],
+            &exit_code
+        );
+        return;
+    }
+
+    // PipedSelect drains both pipes from this thread, polling for readiness instead of
+    // blocking on either stream, so draining them never needs a background thread.
+    if with == With::PipedSelect {
         let child_stdout = child
             .stdout
             .take()
-            .expect("Failed to obtain stdout with PipedProcess");
-
-        let stdout_thread_handle = std::thread::spawn(|| {
-            let mut process_stdout = std::io::stdout();
-            let mut child_reader = BufReader::new(child_stdout).lines();
-            while let Some(Ok(line)) = child_reader.next() {
-                process_stdout
-                    .write_all(format!("{line}\r\n").as_bytes())
-                    .unwrap();
-            }
-        });
+            .expect("Failed to obtain stdout with PipedSelect");
+        let child_stderr = child
+            .stderr
+            .take()
+            .expect("Failed to obtain stderr with PipedSelect");
 
-        // store the handle
-        thread_handles.push(stdout_thread_handle);
+        run_piped_select(child_stdout, child_stderr);
+    }
 
+    // PipedRaw copies raw bytes instead of UTF-8 lines, so binary or non-UTF-8 child output
+    // passes through unmodified instead of being split on newlines and re-encoded as `\r\n`.
+    if with == With::PipedRaw {
+        let child_stdout = child
+            .stdout
+            .take()
+            .expect("Failed to obtain stdout with PipedRaw");
         let child_stderr = child
             .stderr
             .take()
-            .expect("Failed to obtain stderr with PipedProcess");
-
-        let stderr_thread_handle = std::thread::spawn(|| {
-            let mut process_stderr = std::io::stderr();
-            let mut child_reader = BufReader::new(child_stderr).lines();
-            while let Some(Ok(line)) = child_reader.next() {
-                process_stderr
-                    .write_all(format!("{line}\r\n").as_bytes())
-                    .unwrap();
-            }
-        });
+            .expect("Failed to obtain stderr with PipedRaw");
+
+        let stdout_thread_handle =
+            std::thread::spawn(move || copy_raw(child_stdout, &mut std::io::stdout()));
+        let stderr_thread_handle =
+            std::thread::spawn(move || copy_raw(child_stderr, &mut std::io::stderr()));
 
-        // store the handle
-        thread_handles.push(stderr_thread_handle);
+        stdout_thread_handle.join().unwrap();
+        stderr_thread_handle.join().unwrap();
     }
 
-    let exit_code = child
-        .wait()
-        .expect("Command failed to start")
-        .code()
-        .expect("Command did not have a valid exit code");
+    // PipedStream relays each chunk to a connected TCP/WebSocket client as it arrives, so a
+    // remote watcher can follow a long-running command live instead of waiting for it to exit.
+    if with == With::PipedStream {
+        let child_stdout = child
+            .stdout
+            .take()
+            .expect("Failed to obtain stdout with PipedStream");
+        let child_stderr = child
+            .stderr
+            .take()
+            .expect("Failed to obtain stderr with PipedStream");
 
-    // cleanup thread handles, which will only exist if we're implementing the fix
-    if !thread_handles.is_empty() {
-        for handle in thread_handles {
-            handle.join().unwrap();
-        }
+        stream_sink::run_piped_stream(&listen_addr, child_stdout, child_stderr);
     }
 
-    // log what happened
-    println!(
+    let exit_code = exit_code_for(child.wait().expect("Command failed to start"));
+
+    // log what happened on stderr, not stdout - PipedRaw and PipedProcess relay the child's
+    // stdout byte-for-byte, and a summary line mixed into that stream would corrupt binary output.
+    eprintln!(
         "Executed '{:?}' with '{:?}', got exit code '{:?}'",
         &command[0],
         &command[1..],
         &exit_code
     );
 }
+
+/// Computes a process exit code for `status`, handling children terminated by a signal (where
+/// [`ExitStatus::code`](std::process::ExitStatus::code) returns `None`) instead of panicking.
+///
+/// On Unix, a signal-terminated child reports the conventional `128 + signum` exit code. On all
+/// platforms, an exit code that can't be determined at all is reported as a diagnostic rather
+/// than an unwind, so the repro stays usable for observing children killed mid-hang.
+fn exit_code_for(status: std::process::ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            eprintln!("Command was terminated by signal {signal}");
+            return 128 + signal;
+        }
+    }
+
+    eprintln!("Command did not have a valid exit code: {status}");
+    1
+}
+
+/// Copies from `reader` to `writer` using a fixed-size buffer, passing bytes through unmodified.
+///
+/// Unlike `BufReader::lines()`, this makes no assumption about UTF-8 and doesn't normalize line
+/// endings, so binary payloads and non-UTF-8 text survive the trip intact.
+///
+/// A broken pipe on the write side (e.g. a child that exits before consuming everything we
+/// forward to its stdin) just stops the copy rather than panicking, since that's an expected
+/// outcome of the other end going away, not a bug.
+fn copy_raw<R: Read, W: Write>(mut reader: R, mut writer: W) {
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if let Err(e) = writer.write_all(&buf[..n]) {
+                    if e.kind() == std::io::ErrorKind::BrokenPipe {
+                        break;
+                    }
+                    panic!("failed to write child output: {e}");
+                }
+            }
+            Err(e) => panic!("failed to read child output: {e}"),
+        }
+    }
+}
+
+/// Drains `child_stdout` and `child_stderr` to the parent's stdout/stderr from a single thread.
+///
+/// Unlike [`With::PipedProcess`], which needs one thread per stream to avoid blocking reads on
+/// one stream while the other fills, this polls both pipes for readiness and only reads whichever
+/// one actually has data, so a single thread can safely service both without starving either.
+#[cfg(unix)]
+fn run_piped_select(mut child_stdout: ChildStdout, mut child_stderr: ChildStderr) {
+    use std::os::unix::io::AsRawFd;
+
+    let stdout_fd = child_stdout.as_raw_fd();
+    let stderr_fd = child_stderr.as_raw_fd();
+
+    let mut process_stdout = std::io::stdout();
+    let mut process_stderr = std::io::stderr();
+    let mut buf = [0u8; 8192];
+
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+
+    while stdout_open || stderr_open {
+        let mut fds = Vec::with_capacity(2);
+        if stdout_open {
+            fds.push(libc::pollfd {
+                fd: stdout_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+        if stderr_open {
+            fds.push(libc::pollfd {
+                fd: stderr_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+
+        // SAFETY: `fds` holds valid, live pipe fds for the duration of this call.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("poll on child pipes failed: {err}");
+        }
+
+        for pfd in &fds {
+            if pfd.revents & (libc::POLLIN | libc::POLLHUP) == 0 {
+                continue;
+            }
+
+            if pfd.fd == stdout_fd {
+                match child_stdout.read(&mut buf) {
+                    Ok(0) => stdout_open = false,
+                    Ok(n) => process_stdout.write_all(&buf[..n]).unwrap(),
+                    Err(e) => panic!("failed to read child stdout: {e}"),
+                }
+            } else {
+                match child_stderr.read(&mut buf) {
+                    Ok(0) => stderr_open = false,
+                    Ok(n) => process_stderr.write_all(&buf[..n]).unwrap(),
+                    Err(e) => panic!("failed to read child stderr: {e}"),
+                }
+            }
+        }
+    }
+}
+
+/// Windows has no portable equivalent of `poll` over anonymous pipe handles, so instead we
+/// peek each pipe in turn for queued bytes and only block (briefly) when neither has any,
+/// which keeps a single thread from starving either stream.
+#[cfg(windows)]
+fn run_piped_select(mut child_stdout: ChildStdout, mut child_stderr: ChildStderr) {
+    use std::os::windows::io::AsRawHandle;
+    use std::time::Duration;
+
+    mod ffi {
+        use std::os::raw::c_void;
+
+        extern "system" {
+            pub fn PeekNamedPipe(
+                h_named_pipe: *mut c_void,
+                lp_buffer: *mut c_void,
+                n_buffer_size: u32,
+                lp_bytes_read: *mut u32,
+                lp_total_bytes_avail: *mut u32,
+                lp_bytes_left_this_message: *mut u32,
+            ) -> i32;
+        }
+    }
+
+    fn bytes_available(handle: *mut std::os::raw::c_void) -> u32 {
+        let mut available = 0u32;
+        // SAFETY: `handle` is a valid, open pipe handle for the lifetime of this call.
+        let ok = unsafe {
+            ffi::PeekNamedPipe(
+                handle,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                &mut available,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            0
+        } else {
+            available
+        }
+    }
+
+    let stdout_handle = child_stdout.as_raw_handle() as *mut std::os::raw::c_void;
+    let stderr_handle = child_stderr.as_raw_handle() as *mut std::os::raw::c_void;
+
+    let mut process_stdout = std::io::stdout();
+    let mut process_stderr = std::io::stderr();
+    let mut buf = [0u8; 8192];
+
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+
+    while stdout_open || stderr_open {
+        let mut did_work = false;
+
+        if stdout_open && bytes_available(stdout_handle) > 0 {
+            match child_stdout.read(&mut buf) {
+                Ok(0) => stdout_open = false,
+                Ok(n) => {
+                    process_stdout.write_all(&buf[..n]).unwrap();
+                    did_work = true;
+                }
+                Err(e) => panic!("failed to read child stdout: {e}"),
+            }
+        }
+
+        if stderr_open && bytes_available(stderr_handle) > 0 {
+            match child_stderr.read(&mut buf) {
+                Ok(0) => stderr_open = false,
+                Ok(n) => {
+                    process_stderr.write_all(&buf[..n]).unwrap();
+                    did_work = true;
+                }
+                Err(e) => panic!("failed to read child stderr: {e}"),
+            }
+        }
+
+        if !did_work && (stdout_open || stderr_open) {
+            std::thread::sleep(Duration::from_millis(15));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{ChildStderr, ChildStdout};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use sha1::{Digest, Sha1};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long we'll wait for a connected client to send an HTTP upgrade request before assuming
+/// it's a plain watcher that only ever reads.
+const HANDSHAKE_SNIFF_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A client connection to stream child output to: either a plain framed TCP socket, or one
+/// upgraded to a WebSocket connection after a successful handshake.
+enum Sink {
+    Plain(TcpStream),
+    WebSocket(TcpStream),
+}
+
+impl Sink {
+    fn write_chunk(&mut self, tag: &str, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(stream) => {
+                writeln!(stream, "{tag} {}", data.len())?;
+                stream.write_all(data)?;
+                stream.flush()
+            }
+            Sink::WebSocket(stream) => {
+                // prefix the payload with the tag, so a single binary frame carries both which
+                // stream a chunk came from and its bytes. Binary (not text) because child
+                // output isn't guaranteed to be valid UTF-8.
+                let mut framed = Vec::with_capacity(tag.len() + 1 + data.len());
+                framed.extend_from_slice(tag.as_bytes());
+                framed.push(b' ');
+                framed.extend_from_slice(data);
+                write_websocket_frame(stream, &framed)?;
+                stream.flush()
+            }
+        }
+    }
+}
+
+/// Listens on `listen_addr`, accepts a single watcher connection, and streams `child_stdout`
+/// and `child_stderr` to it live as each chunk arrives, draining each pipe on its own background
+/// thread (as `piped-raw` does) so the child never blocks on a full pipe.
+///
+/// Draining starts immediately, before a client has connected (chunks are simply discarded
+/// until then), so a child that writes output right away can't fill its pipe and block while
+/// we're still waiting on [`TcpListener::accept`] or the WebSocket handshake.
+///
+/// If the connecting client sends an HTTP WebSocket upgrade request, the handshake is completed
+/// and chunks are sent as WebSocket binary frames; otherwise chunks are written to the raw
+/// socket as simple `TAG <len>\n<bytes>` frames.
+pub fn run_piped_stream(listen_addr: &str, child_stdout: ChildStdout, child_stderr: ChildStderr) {
+    let listener = TcpListener::bind(listen_addr)
+        .unwrap_or_else(|e| panic!("Failed to listen on {listen_addr}: {e}"));
+    println!("Waiting for a client to connect on {listen_addr}; draining child output in the meantime...");
+
+    let sink: Arc<Mutex<Option<Sink>>> = Arc::new(Mutex::new(None));
+
+    let stdout_sink = Arc::clone(&sink);
+    let stdout_thread = std::thread::spawn(move || stream_chunks(child_stdout, "OUT", &stdout_sink));
+
+    let stderr_sink = Arc::clone(&sink);
+    let stderr_thread = std::thread::spawn(move || stream_chunks(child_stderr, "ERR", &stderr_sink));
+
+    let (stream, addr) = listener
+        .accept()
+        .unwrap_or_else(|e| panic!("Failed to accept client connection: {e}"));
+    println!("Client {addr} connected, streaming output");
+    *sink.lock().unwrap() = Some(accept_sink(stream));
+
+    stdout_thread.join().unwrap();
+    stderr_thread.join().unwrap();
+}
+
+/// Drains `reader` in fixed-size chunks, flushing each one to `sink` as it arrives rather than
+/// buffering until the child exits. Chunks read before `sink` holds a connected client are
+/// discarded, so the child is never blocked waiting on us.
+fn stream_chunks<R: Read>(mut reader: R, tag: &str, sink: &Arc<Mutex<Option<Sink>>>) {
+    let mut buf = [0u8; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let mut guard = sink.lock().unwrap();
+                let disconnected = match guard.as_mut() {
+                    Some(sink) => sink.write_chunk(tag, &buf[..n]).is_err(),
+                    None => false,
+                };
+                if disconnected {
+                    // the watcher disconnected; keep draining the pipe so the child doesn't
+                    // block, we just stop forwarding.
+                    *guard = None;
+                }
+            }
+            Err(e) => panic!("failed to read child output: {e}"),
+        }
+    }
+}
+
+/// Performs the WebSocket handshake if the client's first request looks like an upgrade,
+/// otherwise treats the connection as a plain framed TCP socket.
+///
+/// A genuine watcher client only ever reads, so we can't `peek`/block waiting for it to send
+/// anything — that would hang forever. Instead we give the client a short window to send an
+/// HTTP upgrade request; if nothing arrives in that window, we assume it won't and fall back to
+/// a plain sink. Bytes sniffed this way are consumed (not just peeked), so they don't linger
+/// unread in the socket's receive buffer and turn a later close into a connection reset.
+fn accept_sink(mut stream: TcpStream) -> Sink {
+    if stream.set_read_timeout(Some(HANDSHAKE_SNIFF_TIMEOUT)).is_err() {
+        return Sink::Plain(stream);
+    }
+
+    let mut sniff_buf = [0u8; 1024];
+    let n = stream.read(&mut sniff_buf).unwrap_or(0);
+    // restore blocking reads now that the sniff window is over.
+    let _ = stream.set_read_timeout(None);
+
+    if n == 0 {
+        return Sink::Plain(stream);
+    }
+
+    let request = String::from_utf8_lossy(&sniff_buf[..n]);
+    if !request.starts_with("GET ")
+        || !request.to_ascii_lowercase().contains("upgrade: websocket")
+    {
+        return Sink::Plain(stream);
+    }
+
+    // consume the rest of the HTTP request up to the blank line terminating the headers,
+    // bailing out if a misbehaving client never sends one so we don't grow this buffer without
+    // bound.
+    const MAX_HANDSHAKE_BYTES: usize = 8 * 1024;
+    let mut request_buf = sniff_buf[..n].to_vec();
+    let mut byte = [0u8; 1];
+    while !request_buf.ends_with(b"\r\n\r\n") {
+        if request_buf.len() >= MAX_HANDSHAKE_BYTES {
+            return Sink::Plain(stream);
+        }
+        if stream.read_exact(&mut byte).is_err() {
+            return Sink::Plain(stream);
+        }
+        request_buf.push(byte[0]);
+    }
+
+    let request_text = String::from_utf8_lossy(&request_buf);
+    let key = request_text
+        .lines()
+        .find_map(|line| line.strip_prefix("Sec-WebSocket-Key:"))
+        .map(|v| v.trim().to_string());
+
+    let Some(key) = key else {
+        return Sink::Plain(stream);
+    };
+
+    let accept = {
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(WEBSOCKET_GUID.as_bytes());
+        BASE64.encode(hasher.finalize())
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    if stream.write_all(response.as_bytes()).is_err() {
+        return Sink::Plain(stream);
+    }
+
+    Sink::WebSocket(stream)
+}
+
+/// Writes `payload` as a single, unmasked WebSocket binary frame (servers never mask frames).
+fn write_websocket_frame(stream: &mut TcpStream, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x82); // FIN + binary opcode
+
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)
+}